@@ -4,12 +4,43 @@
 use crate::text::strip_html;
 use blake3::Hasher;
 use lazy_static::lazy_static;
+use pulldown_cmark::{html, Options, Parser};
 use regex::{Captures, Regex};
 use std::borrow::Cow;
+use std::collections::HashMap;
 
 // Filtering
 //----------------------------------------
 
+/// Tracks how many times each generated hint id has been handed out during
+/// the rendering of a single card, so that two hints with identical text
+/// (and thus identical hashes) still get distinct DOM ids. One tracker
+/// should be created per card render and threaded through every call to
+/// [apply_filters] for that card's fields.
+#[derive(Debug, Default)]
+pub(crate) struct HintIdTracker {
+    seen: HashMap<String, u32>,
+}
+
+impl HintIdTracker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `base_id` the first time it's seen, and `base_id` with an
+    /// incrementing `-N` suffix on subsequent calls.
+    fn unique_id(&mut self, base_id: String) -> String {
+        let count = self.seen.entry(base_id.clone()).or_insert(0);
+        let id = if *count == 0 {
+            base_id
+        } else {
+            format!("{}-{}", base_id, count)
+        };
+        *count += 1;
+        id
+    }
+}
+
 /// Applies built in filters, returning the resulting text and remaining filters.
 ///
 /// The first non-standard filter that is encountered will terminate processing,
@@ -18,6 +49,7 @@ pub(crate) fn apply_filters<'a>(
     text: &'a str,
     filters: &[&str],
     field_name: &str,
+    seen_hints: &mut HintIdTracker,
 ) -> (Cow<'a, str>, Vec<String>) {
     let mut text: Cow<str> = text.into();
 
@@ -29,7 +61,7 @@ pub(crate) fn apply_filters<'a>(
     };
 
     for (idx, &filter_name) in filters.iter().enumerate() {
-        match apply_filter(filter_name, text.as_ref(), field_name) {
+        match apply_filter(filter_name, text.as_ref(), field_name, seen_hints) {
             (true, None) => {
                 // filter did not change text
             }
@@ -55,9 +87,15 @@ pub(crate) fn apply_filters<'a>(
 ///
 /// Returns true if filter was valid.
 /// Returns string if input text changed.
-fn apply_filter<'a>(filter_name: &str, text: &'a str, field_name: &str) -> (bool, Option<String>) {
+fn apply_filter<'a>(
+    filter_name: &str,
+    text: &'a str,
+    field_name: &str,
+    seen_hints: &mut HintIdTracker,
+) -> (bool, Option<String>) {
     let output_text = match filter_name {
         "text" => strip_html(text),
+        "markdown" => markdown_filter(text),
         "furigana" => furigana_filter(text),
         "kanji" => kanji_filter(text),
         "kana" => kana_filter(text),
@@ -67,7 +105,8 @@ fn apply_filter<'a>(filter_name: &str, text: &'a str, field_name: &str) -> (bool
             let filter_args = *split.get(1).unwrap_or(&"");
             match base {
                 "type" => type_filter(text, filter_args, field_name),
-                "hint" => hint_filter(text, field_name),
+                "hint" => hint_filter(text, field_name, seen_hints),
+                "regex" => regex_filter(text, filter_args),
                 //"cq" => cloze_filter(text, filter_args, true),
                 //"ca" => cloze_filter(text, filter_args, false),
                 _ => return (false, None),
@@ -84,74 +123,231 @@ fn apply_filter<'a>(filter_name: &str, text: &'a str, field_name: &str) -> (bool
     )
 }
 
+// Markdown filter
+//----------------------------------------
+
+/// Parses the field as CommonMark and renders it to HTML.
+///
+/// Field values often already contain raw HTML left over from the rich text
+/// editor (eg `<b>bold</b>`); pulldown-cmark's renderer passes raw HTML tags
+/// straight through, so this filter is safe to chain after other filters.
+/// Plain-text entity references like `&nbsp;` are not raw HTML and are
+/// decoded to their Unicode character, the same as any other CommonMark
+/// text.
+fn markdown_filter(text: &str) -> Cow<'static, str> {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let parser = Parser::new_ext(text, options);
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, parser);
+    rendered.into()
+}
+
 // Cloze filter
 //----------------------------------------
 
-lazy_static! {
-    static ref CLOZE: Regex = Regex::new(
-        r#"(?xsi)
-                \{\{
-                (c)(\d+)::  # 1 = c or C, 2 = cloze number
-                (.*?) # 3 = clozed text
-                (?:
-                  ::(.*?) # 4 = optional hint
-                )?
-                \}\}
-                "#
-    )
-    .unwrap();
+// A hand-rolled parser is used instead of a single regex, because the
+// previous `\{\{(c)(\d+)::(.*?)(?:::(.*?))?\}\}` pattern is non-greedy and
+// stops at the first `}}`, so it cannot represent a cloze nested inside
+// another one (`{{c1::outer {{c2::inner}}}}`). Instead we tokenize the
+// field into a tree of text runs and cloze nodes, tracking brace depth so
+// that the closing `}}` matching an opening `{{c<N>::` is the one that
+// brings the nesting level back to zero.
+#[derive(Debug, Clone, PartialEq)]
+enum ClozeNode {
+    Text(String),
+    Cloze {
+        c_char: char,
+        ord: u16,
+        content: Vec<ClozeNode>,
+        hint: Option<String>,
+    },
 }
 
-mod cloze_caps {
-    // the lower or uppercase C in the cloze deletion
-    pub static C_CHAR: usize = 1;
-    // cloze ordinal
-    pub static ORD: usize = 2;
-    // the occluded text
-    pub static TEXT: usize = 3;
-    // optional hint
-    pub static HINT: usize = 4;
+/// Finds the next valid cloze opening token (`{{c<digits>::` or
+/// `{{C<digits>::`) at or after `from`, returning the token's start index,
+/// the index just after the trailing `::`, the captured `c`/`C` character,
+/// and the parsed ordinal.
+fn find_cloze_open(s: &str, from: usize) -> Option<(usize, usize, char, u16)> {
+    let mut search_from = from;
+    while let Some(rel) = s[search_from..].find("{{") {
+        let start = search_from + rel;
+        let after_braces = start + 2;
+        if let Some(c) = s[after_braces..].chars().next() {
+            if c == 'c' || c == 'C' {
+                let digits_start = after_braces + c.len_utf8();
+                let digits_len = s[digits_start..]
+                    .bytes()
+                    .take_while(u8::is_ascii_digit)
+                    .count();
+                if digits_len > 0 {
+                    let digits_end = digits_start + digits_len;
+                    if let Ok(ord) = s[digits_start..digits_end].parse::<u16>() {
+                        if s[digits_end..].starts_with("::") {
+                            return Some((start, digits_end + 2, c, ord));
+                        }
+                    }
+                }
+            }
+        }
+        search_from = start + 1;
+    }
+    None
 }
 
-fn reveal_cloze_text(text: &str, ord: u16, question: bool) -> Cow<str> {
-    let output = CLOZE.replace_all(text, |caps: &Captures| {
-        let captured_ord = caps
-            .get(cloze_caps::ORD)
-            .unwrap()
-            .as_str()
-            .parse()
-            .unwrap_or(0);
-
-        if captured_ord != ord {
-            // other cloze deletions are unchanged
-            return caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
+/// Parses the cloze starting at `token_start`/`token_end` (just after its
+/// `::`), returning the node and the index just after its closing `}}`.
+/// Falls back to treating the opening token as plain text if no `}}` brings
+/// the nesting depth back to zero.
+fn parse_cloze_node(
+    s: &str,
+    token_start: usize,
+    token_end: usize,
+    c_char: char,
+    ord: u16,
+) -> (ClozeNode, usize) {
+    let mut depth = 1u32;
+    let mut pos = token_end;
+    let mut last_colon = None;
+
+    loop {
+        let open = find_cloze_open(s, pos);
+        let close = s[pos..].find("}}").map(|rel| pos + rel);
+        let colon = if depth == 1 {
+            s[pos..].find("::").map(|rel| pos + rel)
+        } else {
+            None
+        };
+
+        let mut candidates: Vec<(usize, u8)> = Vec::new();
+        if let Some((start, _, _, _)) = open {
+            candidates.push((start, 0));
+        }
+        if let Some(idx) = close {
+            candidates.push((idx, 1));
+        }
+        if let Some(idx) = colon {
+            candidates.push((idx, 2));
         }
 
-        let mut replacement;
-        if question {
-            // hint provided?
-            if let Some(hint) = caps.get(cloze_caps::HINT) {
-                replacement = format!("[{}]", hint.as_str());
-            } else {
-                replacement = "[...]".to_string()
+        match candidates.into_iter().min() {
+            None => {
+                // unbalanced: no matching closing brace, so the opening token was not
+                // really a cloze deletion; emit just the opening token as
+                // plain text and resume scanning after it, so a later,
+                // independently-closed cloze further in the string is still
+                // found
+                return (
+                    ClozeNode::Text(s[token_start..token_end].to_string()),
+                    token_end,
+                );
+            }
+            Some((idx, 0)) => {
+                depth += 1;
+                pos = open.unwrap().1;
+                let _ = idx;
+            }
+            Some((idx, 1)) => {
+                depth -= 1;
+                if depth == 0 {
+                    let content_end = last_colon.unwrap_or(idx);
+                    let hint = last_colon.map(|colon_idx| s[colon_idx + 2..idx].to_string());
+                    let content = parse_cloze_nodes(&s[token_end..content_end]);
+                    return (
+                        ClozeNode::Cloze {
+                            c_char,
+                            ord,
+                            content,
+                            hint,
+                        },
+                        idx + 2,
+                    );
+                }
+                pos = idx + 2;
+            }
+            Some((idx, 2)) => {
+                last_colon = Some(idx);
+                pos = idx + 2;
+            }
+            Some(_) => unreachable!(),
+        }
+    }
+}
+
+fn parse_cloze_nodes(s: &str) -> Vec<ClozeNode> {
+    let mut nodes = Vec::new();
+    let mut pos = 0;
+
+    while pos < s.len() {
+        match find_cloze_open(s, pos) {
+            Some((start, token_end, c_char, ord)) => {
+                if start > pos {
+                    nodes.push(ClozeNode::Text(s[pos..start].to_string()));
+                }
+                let (node, next_pos) = parse_cloze_node(s, start, token_end, c_char, ord);
+                nodes.push(node);
+                pos = next_pos;
+            }
+            None => {
+                nodes.push(ClozeNode::Text(s[pos..].to_string()));
+                break;
             }
-        } else {
-            replacement = caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
         }
+    }
 
-        let can_use_html = caps.get(cloze_caps::C_CHAR).unwrap().as_str() == "c";
-        if can_use_html {
-            replacement = format!("<span class=cloze>{}</span>", replacement);
+    nodes
+}
+
+fn render_cloze_nodes(nodes: &[ClozeNode], ord: u16, question: bool) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        match node {
+            ClozeNode::Text(text) => out.push_str(text),
+            ClozeNode::Cloze {
+                c_char,
+                ord: node_ord,
+                content,
+                hint,
+            } => {
+                if *node_ord == ord {
+                    let mut replacement = if question {
+                        match hint {
+                            Some(hint) => format!("[{}]", hint),
+                            None => "[...]".to_string(),
+                        }
+                    } else {
+                        render_cloze_nodes(content, ord, question)
+                    };
+                    if *c_char == 'c' {
+                        replacement = format!("<span class=cloze>{}</span>", replacement);
+                    }
+                    out.push_str(&replacement);
+                } else {
+                    // other cloze deletions are unchanged, but still rendered
+                    // so any clozes nested inside them are resolved
+                    out.push_str(&render_cloze_nodes(content, ord, question));
+                }
+            }
         }
+    }
+    out
+}
 
-        replacement
-    });
+fn reveal_cloze_text(text: &str, ord: u16, question: bool) -> Cow<str> {
+    let nodes = parse_cloze_nodes(text);
 
     // if no cloze deletions are found, Anki returns an empty string
-    match output {
-        Cow::Borrowed(_) => "".into(),
-        other => other,
+    if !nodes
+        .iter()
+        .any(|node| matches!(node, ClozeNode::Cloze { .. }))
+    {
+        return "".into();
     }
+
+    render_cloze_nodes(&nodes, ord, question).into()
 }
 
 #[allow(dead_code)]
@@ -160,6 +356,107 @@ fn cloze_filter<'a>(text: &'a str, filter_args: &str, question: bool) -> Cow<'a,
     reveal_cloze_text(text, cloze_ord, question)
 }
 
+// Regex filter
+//----------------------------------------
+
+lazy_static! {
+    // matches $1, ${1} and ${name} capture references in a replacement string
+    static ref CAPTURE_REF: Regex = Regex::new(r"\$\$|\$(?:\{(\w+)\}|(\w+))").unwrap();
+}
+
+/// `regex-<flags>-<pattern>-<replacement>`: a user-configurable find/replace
+/// filter, so template authors can rewrite field text without a custom
+/// add-on. `<flags>` is a run of `i` (case insensitive), `m` (multi-line),
+/// `s` (dot matches newline) and `l` (treat `<pattern>` as a literal string
+/// rather than a regex). `<replacement>` may reference capture groups with
+/// `$1` or `${name}`, and is the last component, so may itself contain `-`
+/// (`<pattern>` may not, as it would be ambiguous with the field separator;
+/// use `\x2d` or a character class for a literal hyphen there).
+fn regex_filter<'a>(text: &'a str, filter_args: &str) -> Cow<'a, str> {
+    let mut parts = filter_args.splitn(3, '-');
+    let flags = parts.next().unwrap_or("");
+    let pattern = parts.next().unwrap_or("");
+    let replacement = parts.next().unwrap_or("");
+
+    let literal = flags.contains('l');
+    let inline_flags: String = flags
+        .chars()
+        .filter(|c| matches!(c, 'i' | 'm' | 's'))
+        .collect();
+
+    let pattern = if literal {
+        regex::escape(pattern)
+    } else {
+        pattern.to_string()
+    };
+    let pattern = if inline_flags.is_empty() {
+        pattern
+    } else {
+        format!("(?{}){}", inline_flags, pattern)
+    };
+
+    let re = match Regex::new(&pattern) {
+        Ok(re) => re,
+        // invalid pattern; leave the field untouched
+        Err(_) => return text.into(),
+    };
+
+    let replacement = unescape_replacement(replacement);
+    if !replacement_refs_are_valid(&re, &replacement) {
+        // the replacement references a capture group the pattern doesn't
+        // have; leave the field untouched instead of letting the regex
+        // crate silently drop the reference
+        return text.into();
+    }
+
+    re.replace_all(text, replacement.as_str())
+        .into_owned()
+        .into()
+}
+
+/// Unescapes `\n`, `\t`, `\r` and `\\` in a replacement string, so that
+/// templates can embed them literally even though the field editor only
+/// stores a single line of text.
+fn unescape_replacement(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Checks that every `$1`/`${name}` reference in `replacement` names a
+/// capture group that actually exists in `re`.
+fn replacement_refs_are_valid(re: &Regex, replacement: &str) -> bool {
+    CAPTURE_REF.captures_iter(replacement).all(|caps| {
+        let reference = match caps.get(1).or_else(|| caps.get(2)) {
+            Some(reference) => reference.as_str(),
+            // a `$$` escape (a literal `$`), not a capture reference
+            None => return true,
+        };
+        if let Ok(index) = reference.parse::<usize>() {
+            index < re.captures_len()
+        } else {
+            re.capture_names().flatten().any(|name| name == reference)
+        }
+    })
+}
+
 // Ruby filters
 //----------------------------------------
 
@@ -229,16 +526,22 @@ fn type_filter<'a>(_text: &'a str, filter_args: &str, field_name: &str) -> Cow<'
 }
 
 // fixme: i18n
-fn hint_filter<'a>(text: &'a str, field_name: &str) -> Cow<'a, str> {
+fn hint_filter<'a>(
+    text: &'a str,
+    field_name: &str,
+    seen_hints: &mut HintIdTracker,
+) -> Cow<'a, str> {
     if text.trim().is_empty() {
         return text.into();
     }
 
-    // generate a unique DOM id
+    // hash the text+field name for a deterministic id, then disambiguate it
+    // against any identical hint already emitted earlier in this card
     let mut hasher = Hasher::new();
     hasher.update(text.as_bytes());
     hasher.update(field_name.as_bytes());
-    let id = hex::encode(&hasher.finalize().as_bytes()[0..8]);
+    let base_id = hex::encode(&hasher.finalize().as_bytes()[0..8]);
+    let id = seen_hints.unique_id(base_id);
 
     format!(
         r##"
@@ -261,7 +564,7 @@ return false;">
 mod test {
     use crate::template_filters::{
         apply_filters, cloze_filter, furigana_filter, hint_filter, kana_filter, kanji_filter,
-        type_filter,
+        markdown_filter, regex_filter, type_filter, HintIdTracker,
     };
 
     #[test]
@@ -277,8 +580,9 @@ mod test {
 
     #[test]
     fn test_hint() {
+        let mut seen_hints = HintIdTracker::new();
         assert_eq!(
-            hint_filter("foo", "field"),
+            hint_filter("foo", "field", &mut seen_hints),
             r##"
 <a class=hint href="#"
 onclick="this.style.display='none';
@@ -290,6 +594,25 @@ foo</a>
         );
     }
 
+    #[test]
+    fn test_hint_uniqueness() {
+        // two hints with identical text+field name collide on the same hash,
+        // so the second (and third, ...) must get a disambiguating suffix
+        let mut seen_hints = HintIdTracker::new();
+        let first = hint_filter("foo", "field", &mut seen_hints);
+        let second = hint_filter("foo", "field", &mut seen_hints);
+        let third = hint_filter("foo", "field", &mut seen_hints);
+        assert!(first.contains(r#"id="hint83fe48607f0f3a66""#));
+        assert!(second.contains(r#"id="hint83fe48607f0f3a66-1""#));
+        assert!(third.contains(r#"id="hint83fe48607f0f3a66-2""#));
+
+        // a different render starts the count over
+        let mut other_render = HintIdTracker::new();
+        assert!(
+            hint_filter("foo", "field", &mut other_render).contains(r#"id="hint83fe48607f0f3a66""#)
+        );
+    }
+
     #[test]
     fn test_type() {
         assert_eq!(type_filter("ignored", "", "Front"), "[[type:Front]]");
@@ -298,7 +621,12 @@ foo</a>
             "[[type:cloze:Front]]"
         );
         assert_eq!(
-            apply_filters("ignored", &["type", "cloze"], "Text"),
+            apply_filters(
+                "ignored",
+                &["type", "cloze"],
+                "Text",
+                &mut HintIdTracker::new()
+            ),
             ("[[type:cloze:Text]]".into(), vec![])
         );
     }
@@ -318,4 +646,82 @@ foo</a>
             "<span class=cloze>[...]</span> two"
         );
     }
+
+    #[test]
+    fn test_cloze_nested() {
+        let text = "{{c1::outer {{c2::inner}}}}";
+        // question side: the active cloze is fully hidden, nested or not;
+        // lowercase `c` wraps the active node in a span, at any nesting level
+        assert_eq!(
+            cloze_filter(text, "1", true),
+            "<span class=cloze>[...]</span>"
+        );
+        // the inactive outer cloze is transparent, revealing the active inner one
+        assert_eq!(
+            cloze_filter(text, "2", true),
+            "outer <span class=cloze>[...]</span>"
+        );
+        // answer side: the active cloze reveals its content, recursing into
+        // any clozes nested inside it
+        assert_eq!(
+            cloze_filter(text, "1", false),
+            "<span class=cloze>outer inner</span>"
+        );
+        assert_eq!(
+            cloze_filter(text, "2", false),
+            "outer <span class=cloze>inner</span>"
+        );
+
+        // a hint on an outer cloze is unaffected by further nesting
+        let text = "{{c1::outer {{c2::inner}}::hint}}";
+        assert_eq!(
+            cloze_filter(text, "1", true),
+            "<span class=cloze>[hint]</span>"
+        );
+    }
+
+    #[test]
+    fn test_cloze_unbalanced() {
+        // an unterminated cloze should not swallow a later, well-formed one
+        let text = "{{c1::foo {{c2::bar}}";
+        assert_eq!(
+            cloze_filter(text, "2", true),
+            "{{c1::foo <span class=cloze>[...]</span>"
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        assert_eq!(regex_filter("foo bar", "-foo-baz"), "baz bar");
+        // flags
+        assert_eq!(regex_filter("FOO bar", "i-foo-baz"), "baz bar");
+        // capture references; replacement may contain the separator `-`
+        assert_eq!(
+            regex_filter("2024/01/02", r"-(\d+)/(\d+)/(\d+)-$3-$2-$1"),
+            "02-01-2024"
+        );
+        // literal mode escapes regex metacharacters in the pattern
+        assert_eq!(regex_filter("1 + 1 = 2", "l-+-plus"), "1 plus 1 = 2");
+        // unescaped \n in the replacement becomes a real newline
+        assert_eq!(regex_filter("a,b", r"-,-\n"), "a\nb");
+        // a replacement referencing a group the pattern doesn't have is a no-op
+        assert_eq!(regex_filter("foo bar", "-foo-$9"), "foo bar");
+        // `$$` is a literal `$`, not a capture reference
+        assert_eq!(regex_filter("abc", r"-abc-$$name"), "$name");
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            markdown_filter("**bold** and *italic*").as_ref(),
+            "<p><strong>bold</strong> and <em>italic</em></p>\n"
+        );
+        // raw HTML tags already present in the field are passed through
+        // unchanged, but an entity reference like &nbsp; is decoded to its
+        // Unicode character, same as any other CommonMark text
+        assert_eq!(
+            markdown_filter("foo&nbsp;<b>bar</b>").as_ref(),
+            "<p>foo\u{a0}<b>bar</b></p>\n"
+        );
+    }
 }